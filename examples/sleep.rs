@@ -8,7 +8,7 @@ extern crate panic_semihosting;
 use stm32f1xx_hal::{
     prelude::*,
     pac,
-    sleep::{SleepModeBuilder, SleepMode, SleepModeEntry},
+    sleep::{self, SleepModeBuilder, SleepMode, SleepModeEntry},
     rtc::Rtc,
 };
 use cortex_m_rt::entry;
@@ -29,11 +29,17 @@ fn main() -> ! {
 
     // Determine if we're woken up by WKUP pin or RTC
     let wakeup_flag = pwr.csr.read().wuf().bit();
-    if wakeup_flag {
-        hprintln!("Woke up.").unwrap();
+
+    // Standby resets the MCU, so `phase` itself doesn't survive the reset;
+    // recover it from the backup registers instead.
+    let mut phase = [0u16; 1];
+    let phase = if wakeup_flag && sleep::restore_context(&backup_domain, &mut phase).is_some() {
+        hprintln!("Woke up, resuming at phase {}.", phase[0]).unwrap();
+        phase[0]
     } else {
         hprintln!("Cold boot.").unwrap();
-    }
+        0
+    };
 
     let mut scb = cp.SCB;
     let mut nvic = cp.NVIC;
@@ -46,19 +52,21 @@ fn main() -> ! {
         // Now go to sleep...
         hprintln!("Going to sleep for 10 seconds (or until you pull PA0 high).").unwrap();
 
-        SleepModeBuilder::new(
+        let mut builder = SleepModeBuilder::new(
                 SleepMode::Standby,
                 SleepModeEntry::WFI,
                 &mut scb,
-                &mut pwr)
-            .enable_wakeup_alarm(10, &mut rtc, &mut nvic, &mut exti)
-            .enable_wakeup_pin(&mut nvic, &mut exti, &mut rcc.apb1)
+                &mut pwr);
+        builder.save_context(&mut backup_domain, &[phase.wrapping_add(1)]);
+        let slept_secs = builder
+            .enable_wakeup_alarm(10, &mut rtc)
+            .enable_wakeup_pin(&mut rcc.apb1)
             .enable_debug(&mut dbgmcu)
-            .enter();
+            .enter(&mut exti, &mut nvic);
 
         // Waking up from Standby will reset the MCU (control returns to main),
         // while waking up from Sleep or Stop mode will continue executing any
         // code here...
-        hprintln!("Woke up from Sleep or Stop mode.").unwrap();
+        hprintln!("Woke up from Sleep or Stop mode after {} s.", slept_secs).unwrap();
     }
 }
@@ -2,7 +2,6 @@
  * TODO:
  * - What to do when we do not have the NVIC? (RTFM)
  * - Support for USB and Ethernet wakeup events
- * - Separate function for slowing down system clocks?
  * - Separate function for SLEEPONEXIT?
  * - Separate function to put GPIO's in analog input mode?
  */
@@ -11,13 +10,46 @@ use cortex_m::asm;
 use cortex_m::peripheral::SCB;
 use cortex_m::peripheral::NVIC;
 use crate::{
-    pac::{EXTI, PWR, DBGMCU},
+    pac::{EXTI, PWR, DBGMCU, RCC, FLASH},
     prelude::*,
     rtc::Rtc,
     rcc::{Rcc, APB1},
-    pac::Interrupt::{EXTI0, EXTI3, RTC, RTCALARM},
+    time::Hertz,
+    afio,
+    backup_domain::BackupDomain,
+    pac::Interrupt::{EXTI0, EXTI1, EXTI2, EXTI3, EXTI4, EXTI9_5, EXTI15_10, RTC, RTCALARM},
 };
 
+/// HSI (internal 8 MHz RC oscillator) frequency in Hz. `with_scaled_clocks`
+/// switches SYSCLK to HSI so it can slow down without waiting on a PLL
+/// relock, so the achievable target frequencies are divisions of this.
+const HSI_HZ: u32 = 8_000_000;
+
+/// Number of 16-bit backup data registers [`SleepModeBuilder::save_context`]
+/// can use to store caller state. One further register (not counted here)
+/// holds the magic value that lets [`restore_context`] tell a genuine
+/// wakeup-with-saved-context apart from a cold boot.
+pub const MAX_CONTEXT_WORDS: usize = 9;
+
+/// Written to the first backup data register by `save_context` so
+/// `restore_context` can recognise a valid saved context; any other value
+/// (including the power-on-reset value of the backup domain) means no
+/// context was saved.
+const CONTEXT_MAGIC: u16 = 0xC0DE;
+
+/// Maximum number of [`WakeSource`]s a single [`SleepModeBuilder`] can
+/// collect. Kept small and fixed-capacity so the builder stays `'static`-free
+/// and allocation-free.
+const MAX_WAKE_SOURCES: usize = 4;
+
+/// Maximum number of EXTI lines a single [`SleepModeBuilder`] can track in
+/// its [`WakeTriggers`]: up to [`MAX_WAKE_SOURCES`] from
+/// [`SleepModeBuilder::add_wake_source`], plus one for every EXTI line
+/// (0..=15) armed directly via
+/// [`enable_wakeup_gpio`](SleepModeBuilder::enable_wakeup_gpio), which
+/// doesn't go through the `wake_sources` array and so isn't bounded by
+/// `MAX_WAKE_SOURCES`.
+const MAX_WAKE_TRIGGERS: usize = MAX_WAKE_SOURCES + 16;
 
 /// Sleep modes in descending order of power usage and in ascending or order of
 /// wakeup time.
@@ -29,11 +61,403 @@ pub enum SleepMode {
     Standby,          // All 1.8V domain clocks off, voltage regulator OFF
 }
 
+#[derive(Clone, Copy)]
 pub enum SleepModeEntry {
     WFI, // Wait for Interrupt
     WFE, // Wait for Event, offers the lowest wakeup time
 }
 
+/// A source of a wakeup event.
+///
+/// Implementors own a single EXTI line and know how to arm it: set the
+/// IMR/EMR mask bit, pick the edge trigger, clear any stale pending bit and
+/// enable the corresponding interrupt in the NVIC. This lets
+/// [`SleepModeBuilder`] support new wakeup sources (USB, Ethernet, ...) by
+/// adding a type that implements this trait, instead of growing a new
+/// builder method for each one.
+pub trait WakeSource {
+    /// The EXTI line this source arms. Used to populate a [`WakeTriggers`]
+    /// as soon as the source is added to a [`SleepModeBuilder`], independent
+    /// of when [`apply`](Self::apply) actually runs.
+    fn line(&self) -> u8;
+
+    /// Configure `pwr`/`exti`/`nvic` so this source can wake the MCU from
+    /// `entry` while it is asleep.
+    fn apply(&self, pwr: &mut PWR, exti: &mut EXTI, nvic: &mut NVIC, entry: SleepModeEntry);
+}
+
+/// Tracks which EXTI lines have been armed by the [`WakeSource`]s attached to
+/// a [`SleepModeBuilder`].
+#[derive(Default)]
+pub struct WakeTriggers {
+    lines: [Option<u8>; MAX_WAKE_TRIGGERS],
+    len: usize,
+}
+
+impl WakeTriggers {
+    fn record(&mut self, line: u8) {
+        if self.len < self.lines.len() {
+            self.lines[self.len] = Some(line);
+            self.len += 1;
+        }
+    }
+
+    /// The EXTI lines armed so far, in the order their wake sources were
+    /// added.
+    pub fn lines(&self) -> &[Option<u8>] {
+        &self.lines[..self.len]
+    }
+}
+
+/// Wake up the MCU using the RTC alarm (EXTI line 17).
+///
+/// Note: the RTC alarm still needs to be set with `rtc.set_alarm()` (when
+/// the source is added via [`SleepModeBuilder::enable_wakeup_alarm`],
+/// [`SleepModeBuilder::enter`] does this for you); this type only arms the
+/// EXTI/NVIC side of the wakeup path.
+pub struct RtcAlarmWakeup;
+
+impl WakeSource for RtcAlarmWakeup {
+    fn line(&self) -> u8 {
+        17
+    }
+
+    fn apply(&self, _pwr: &mut PWR, exti: &mut EXTI, nvic: &mut NVIC, entry: SleepModeEntry) {
+        // Enable RTC interrupt in NVIC
+        nvic.enable(RTC);
+        NVIC::unpend(RTC);
+        nvic.enable(RTCALARM);
+        NVIC::unpend(RTCALARM);
+
+        // 1. Enable line 17 (RTC alarm) in IMR or EMR
+        match entry {
+            SleepModeEntry::WFI =>
+                // Interrupt Mask Register
+                exti.imr.modify(|_, w| w.mr17().set_bit()),
+            SleepModeEntry::WFE =>
+                // Event Mask Register
+                exti.emr.modify(|_, w| w.mr17().set_bit()),
+        };
+
+        // 2. Enable rising edge trigger on line 17
+        exti.rtsr.modify(|_, w| w.tr17().set_bit());
+
+        // 3. Clear pending bit for line 17
+        exti.pr.modify(|_, w| w.pr17().set_bit());
+    }
+}
+
+/// Wake up the MCU using the dedicated WKUP pin (PA0).
+pub struct WakeupPinWakeup;
+
+impl WakeSource for WakeupPinWakeup {
+    fn line(&self) -> u8 {
+        0
+    }
+
+    fn apply(&self, _pwr: &mut PWR, exti: &mut EXTI, nvic: &mut NVIC, entry: SleepModeEntry) {
+        // 0. Enable EXTI0
+        nvic.enable(EXTI0);
+        NVIC::unpend(EXTI0);
+
+        // 1. Enable line 0 (PA0) in the IMR or EMR
+        match entry {
+            SleepModeEntry::WFI =>
+                // Interrupt Mask Register
+                exti.imr.modify(|_, w| w.mr0().set_bit()),
+            SleepModeEntry::WFE =>
+                // Event Mask Register
+                exti.emr.modify(|_, w| w.mr0().set_bit()),
+        }
+
+        // 2. Enable rising edge trigger on line 0
+        exti.rtsr.modify(|_, w| w.tr0().set_bit());
+
+        // 3. Clear pending bit for line 0
+        exti.pr.modify(|_, w| w.pr0().set_bit());
+    }
+}
+
+/// Wake up the MCU on an edge (rising by default) of an arbitrary EXTI line
+/// (0..=15).
+///
+/// Useful for a GPIO line when the pin is already configured as an input
+/// elsewhere, or when the caller already knows the line number and doesn't
+/// need [`WakeupPin`] to look it up from a pin type.
+pub struct ExtiLineWakeup {
+    line: u8,
+    edge: WakeupEdge,
+}
+
+impl ExtiLineWakeup {
+    /// `line` must be in the range `0..=15` (the only lines `exti_arm_line`
+    /// can configure; lines 18/19 for USB/Ethernet wakeup are not wired up
+    /// yet, see the module-level TODO). Wakes on a rising edge; use
+    /// [`with_edge`](Self::with_edge) to select a different trigger.
+    pub fn new(line: u8) -> Self {
+        ExtiLineWakeup { line, edge: WakeupEdge::Rising }
+    }
+
+    /// Like [`new`](Self::new), but triggering on `edge` instead of always
+    /// rising.
+    pub fn with_edge(line: u8, edge: WakeupEdge) -> Self {
+        ExtiLineWakeup { line, edge }
+    }
+}
+
+impl WakeSource for ExtiLineWakeup {
+    fn line(&self) -> u8 {
+        self.line
+    }
+
+    fn apply(&self, _pwr: &mut PWR, exti: &mut EXTI, nvic: &mut NVIC, entry: SleepModeEntry) {
+        exti_arm_line(exti, nvic, self.line, entry, self.edge);
+    }
+}
+
+/// Implemented by GPIO input pin types so an owned, already-configured pin
+/// can be used directly with [`SleepModeBuilder::enable_wakeup_gpio`]
+/// without the caller having to look up which EXTI line it's wired to.
+/// Implemented below for every `gpio{a..e}::P{A..E}{0..15}<Input<MODE>>`.
+pub trait WakeupPin {
+    /// The EXTI line (0..=15) this pin's pin-number is wired to, e.g. PB3
+    /// and PC3 both report line 3.
+    fn exti_line(&self) -> u8;
+
+    /// This pin's GPIO port, as an AFIO_EXTICRx port selector value (0 for
+    /// GPIOA, 1 for GPIOB, ..., see RM0008 section 9.4.1 "AFIO external
+    /// interrupt configuration register"). `exti_line` is time-shared across
+    /// all ports, so this is needed to route it to the right one.
+    fn afio_port(&self) -> u8;
+}
+
+/// Every GPIO input pin is wired to the EXTI line matching its pin number
+/// (PA3, PB3, PC3, ... all share line 3 -- see RM0008 section 10.1.1, "the
+/// GPIO port to be connected to the EXTI line is selected through..."). This
+/// generates a [`WakeupPin`] impl for each `P$port$i<Input<MODE>>` pin type
+/// so any already-configured input pin can be passed to
+/// [`SleepModeBuilder::enable_wakeup_gpio`] directly.
+macro_rules! wakeup_pins {
+    ($($port:ident ($afio_port:expr): [$($Pin:ident => $line:expr),+ $(,)?]),+ $(,)?) => {
+        $(
+            $(
+                impl<MODE> WakeupPin for crate::gpio::$port::$Pin<crate::gpio::Input<MODE>> {
+                    fn exti_line(&self) -> u8 {
+                        $line
+                    }
+
+                    fn afio_port(&self) -> u8 {
+                        $afio_port
+                    }
+                }
+            )+
+        )+
+    };
+}
+
+wakeup_pins! {
+    gpioa (0): [
+        PA0 => 0, PA1 => 1, PA2 => 2, PA3 => 3, PA4 => 4, PA5 => 5, PA6 => 6, PA7 => 7,
+        PA8 => 8, PA9 => 9, PA10 => 10, PA11 => 11, PA12 => 12, PA13 => 13, PA14 => 14, PA15 => 15,
+    ],
+    gpiob (1): [
+        PB0 => 0, PB1 => 1, PB2 => 2, PB3 => 3, PB4 => 4, PB5 => 5, PB6 => 6, PB7 => 7,
+        PB8 => 8, PB9 => 9, PB10 => 10, PB11 => 11, PB12 => 12, PB13 => 13, PB14 => 14, PB15 => 15,
+    ],
+    gpioc (2): [
+        PC0 => 0, PC1 => 1, PC2 => 2, PC3 => 3, PC4 => 4, PC5 => 5, PC6 => 6, PC7 => 7,
+        PC8 => 8, PC9 => 9, PC10 => 10, PC11 => 11, PC12 => 12, PC13 => 13, PC14 => 14, PC15 => 15,
+    ],
+    gpiod (3): [
+        PD0 => 0, PD1 => 1, PD2 => 2, PD3 => 3, PD4 => 4, PD5 => 5, PD6 => 6, PD7 => 7,
+        PD8 => 8, PD9 => 9, PD10 => 10, PD11 => 11, PD12 => 12, PD13 => 13, PD14 => 14, PD15 => 15,
+    ],
+    gpioe (4): [
+        PE0 => 0, PE1 => 1, PE2 => 2, PE3 => 3, PE4 => 4, PE5 => 5, PE6 => 6, PE7 => 7,
+        PE8 => 8, PE9 => 9, PE10 => 10, PE11 => 11, PE12 => 12, PE13 => 13, PE14 => 14, PE15 => 15,
+    ],
+}
+
+/// Route EXTI `line` (0..=15) to `port` (an [`WakeupPin::afio_port`] value)
+/// via AFIO_EXTICRx, so the line's trigger actually watches the intended
+/// GPIO port rather than whatever port the mux last happened to point it at
+/// (RM0008 section 9.4.1; each register covers 4 lines, so line determines
+/// both the register and the field within it).
+fn afio_route_line(afio: &mut afio::Parts, line: u8, port: u8) {
+    macro_rules! route {
+        ($reg:ident, $field:ident) => {
+            afio.$reg.modify(|_, w| unsafe { w.$field().bits(port) })
+        };
+    }
+
+    match line {
+        0 => route!(exticr1, exti0),
+        1 => route!(exticr1, exti1),
+        2 => route!(exticr1, exti2),
+        3 => route!(exticr1, exti3),
+        4 => route!(exticr2, exti4),
+        5 => route!(exticr2, exti5),
+        6 => route!(exticr2, exti6),
+        7 => route!(exticr2, exti7),
+        8 => route!(exticr3, exti8),
+        9 => route!(exticr3, exti9),
+        10 => route!(exticr3, exti10),
+        11 => route!(exticr3, exti11),
+        12 => route!(exticr4, exti12),
+        13 => route!(exticr4, exti13),
+        14 => route!(exticr4, exti14),
+        15 => route!(exticr4, exti15),
+        _ => unimplemented!("EXTI line {} is not a GPIO-capable line", line),
+    }
+}
+
+/// Which edge(s) of an EXTI line should trigger a wakeup.
+#[derive(Clone, Copy, PartialEq)]
+pub enum WakeupEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// Arm EXTI `line` to wake the MCU from `entry` on `edge`, and enable its
+/// interrupt in the NVIC. Shared by [`ExtiLineWakeup`] and
+/// [`SleepModeBuilder::enable_wakeup_gpio`].
+fn exti_arm_line(exti: &mut EXTI, nvic: &mut NVIC, line: u8, entry: SleepModeEntry, edge: WakeupEdge) {
+    macro_rules! arm {
+        ($mr:ident, $tr:ident, $pr:ident, $interrupt:expr) => {{
+            nvic.enable($interrupt);
+            NVIC::unpend($interrupt);
+
+            match entry {
+                SleepModeEntry::WFI => exti.imr.modify(|_, w| w.$mr().set_bit()),
+                SleepModeEntry::WFE => exti.emr.modify(|_, w| w.$mr().set_bit()),
+            }
+
+            match edge {
+                WakeupEdge::Rising => {
+                    exti.rtsr.modify(|_, w| w.$tr().set_bit());
+                    exti.ftsr.modify(|_, w| w.$tr().clear_bit());
+                }
+                WakeupEdge::Falling => {
+                    exti.rtsr.modify(|_, w| w.$tr().clear_bit());
+                    exti.ftsr.modify(|_, w| w.$tr().set_bit());
+                }
+                WakeupEdge::Both => {
+                    exti.rtsr.modify(|_, w| w.$tr().set_bit());
+                    exti.ftsr.modify(|_, w| w.$tr().set_bit());
+                }
+            }
+
+            exti.pr.modify(|_, w| w.$pr().set_bit());
+        }};
+    }
+
+    // EXTI0..4 each have their own NVIC line; EXTI5..9 and EXTI10..15 share
+    // one NVIC line each (see RM0008 table "Vector table").
+    match line {
+        0 => arm!(mr0, tr0, pr0, EXTI0),
+        1 => arm!(mr1, tr1, pr1, EXTI1),
+        2 => arm!(mr2, tr2, pr2, EXTI2),
+        3 => arm!(mr3, tr3, pr3, EXTI3),
+        4 => arm!(mr4, tr4, pr4, EXTI4),
+        5 => arm!(mr5, tr5, pr5, EXTI9_5),
+        6 => arm!(mr6, tr6, pr6, EXTI9_5),
+        7 => arm!(mr7, tr7, pr7, EXTI9_5),
+        8 => arm!(mr8, tr8, pr8, EXTI9_5),
+        9 => arm!(mr9, tr9, pr9, EXTI9_5),
+        10 => arm!(mr10, tr10, pr10, EXTI15_10),
+        11 => arm!(mr11, tr11, pr11, EXTI15_10),
+        12 => arm!(mr12, tr12, pr12, EXTI15_10),
+        13 => arm!(mr13, tr13, pr13, EXTI15_10),
+        14 => arm!(mr14, tr14, pr14, EXTI15_10),
+        15 => arm!(mr15, tr15, pr15, EXTI15_10),
+        _ => unimplemented!("EXTI line {} is not a GPIO-capable line", line),
+    }
+}
+
+/// Clear the EXTI pending bit for `line`, without touching its mask/trigger
+/// configuration. Used by [`SleepModeBuilder::clear_pending_events`].
+fn exti_clear_pending(exti: &mut EXTI, line: u8) {
+    macro_rules! clear {
+        ($pr:ident) => {
+            exti.pr.modify(|_, w| w.$pr().set_bit())
+        };
+    }
+
+    match line {
+        0 => clear!(pr0),
+        1 => clear!(pr1),
+        2 => clear!(pr2),
+        3 => clear!(pr3),
+        4 => clear!(pr4),
+        5 => clear!(pr5),
+        6 => clear!(pr6),
+        7 => clear!(pr7),
+        8 => clear!(pr8),
+        9 => clear!(pr9),
+        10 => clear!(pr10),
+        11 => clear!(pr11),
+        12 => clear!(pr12),
+        13 => clear!(pr13),
+        14 => clear!(pr14),
+        15 => clear!(pr15),
+        17 => clear!(pr17),
+        _ => unimplemented!("EXTI line {} is not wired up yet", line),
+    }
+}
+
+/// The HPRE (AHB prescaler) bits that divide HSI down to at most
+/// `target_hz`, per RM0008 section "7.3.2 Clock configuration register".
+/// Targets below HSI/512 get the largest prescaler, /512.
+fn hpre_bits_for(target_hz: Hertz) -> u8 {
+    let target = target_hz.0.max(1);
+    // Round up, so the chosen prescaler never leaves HCLK above `target_hz`.
+    let divider = (HSI_HZ / target + if HSI_HZ % target != 0 { 1 } else { 0 }).max(1);
+    match divider {
+        d if d <= 1 => 0b0000,   // /1
+        d if d <= 2 => 0b1000,   // /2
+        d if d <= 4 => 0b1001,   // /4
+        d if d <= 8 => 0b1010,   // /8
+        d if d <= 16 => 0b1011,  // /16
+        d if d <= 64 => 0b1100,  // /64
+        d if d <= 128 => 0b1101, // /128
+        d if d <= 256 => 0b1110, // /256
+        _ => 0b1111,             // /512
+    }
+}
+
+/// A snapshot of the clock tree taken by
+/// [`SleepModeBuilder::with_scaled_clocks`], so it can be reprogrammed back
+/// to exactly the pre-sleep state after waking up.
+///
+/// Only relevant for [`SleepMode::Sleep`]/[`SleepMode::StopRegulatorOn`]/
+/// [`SleepMode::StopRegulatorLP`], which resume execution in place; Standby
+/// resets the MCU, so there is nothing left to restore.
+pub struct ScaledClocksGuard {
+    cfgr: u32,
+    flash_latency: u8,
+}
+
+impl ScaledClocksGuard {
+    /// Reprogram RCC_CFGR (clock source, HPRE, PPRE1, PPRE2) and the flash
+    /// wait states back to what they were before `with_scaled_clocks` was
+    /// called. Call this as the first thing after waking from Sleep/Stop,
+    /// before touching any peripheral whose timing depends on the clock
+    /// tree.
+    pub fn restore(self) {
+        // Safety: these are exactly the bits `with_scaled_clocks` snapshotted
+        // and changed; nothing else in this crate writes RCC_CFGR or
+        // FLASH_ACR's latency field after `Rcc::freeze` runs.
+        let rcc = unsafe { &*RCC::ptr() };
+        let flash = unsafe { &*FLASH::ptr() };
+
+        // Raise the wait states back up before raising the clock speed.
+        flash.acr.modify(|_, w| unsafe { w.latency().bits(self.flash_latency) });
+        rcc.cfgr.write(|w| unsafe { w.bits(self.cfgr) });
+        while rcc.cfgr.read().sws().bits() != rcc.cfgr.read().sw().bits() {}
+    }
+}
+
 pub struct SleepModeBuilder<'a> {
     sleep_mode: SleepMode,
     sleep_mode_entry: SleepModeEntry,
@@ -41,6 +465,9 @@ pub struct SleepModeBuilder<'a> {
     pwr: &'a mut PWR,
     rtc: Option<&'a mut Rtc>,
     wakeup_alarm: Option<u32>,
+    wake_sources: [Option<&'a dyn WakeSource>; MAX_WAKE_SOURCES],
+    wake_source_count: usize,
+    wake_triggers: WakeTriggers,
 }
 
 impl<'a> SleepModeBuilder<'a> {
@@ -95,73 +522,71 @@ impl<'a> SleepModeBuilder<'a> {
 
         SleepModeBuilder {
             sleep_mode, sleep_mode_entry, scb, pwr, rtc: None, wakeup_alarm: None,
+            wake_sources: [None; MAX_WAKE_SOURCES],
+            wake_source_count: 0,
+            wake_triggers: WakeTriggers::default(),
         }
     }
 
-    /// Wake up the MCU using the RTC alarm. Note: the user needs to call
-    /// `rtc.set_alarm()`!
-    pub fn enable_wakeup_alarm(mut self, secs: u32, rtc: &'a mut Rtc, nvic: &mut NVIC, exti: &mut EXTI) -> Self {
-        self.wakeup_alarm = Some(secs);
-        self.rtc = Some(rtc);
-
-        if self.is_sleep_or_stop_mode() {
-            // Enable RTC interrupt in NVIC
-            nvic.enable(RTC);
-            NVIC::unpend(RTC);
-            nvic.enable(RTCALARM);
-            NVIC::unpend(RTCALARM);
-
-            // 1. Enable line 17 (RTC alarm) in IMR or EMR
-            match self.sleep_mode_entry {
-                SleepModeEntry::WFI =>
-                    // Interrupt Mask Register
-                    exti.imr.modify(|_, w| w.mr17().set_bit()),
-                SleepModeEntry::WFE =>
-                    // Event Mask Register
-                    exti.emr.modify(|_, w| w.mr17().set_bit()),
-            };
-
-            // 2. Enable rising edge trigger on line 17
-            exti.rtsr.modify(|_, w| w.tr17().set_bit());
-
-            // 3. Clear pending bit for line 17
-            exti.pr.modify(|_, w| w.pr17().set_bit());
+    /// Add a [`WakeSource`] that should be armed when [`enter`](Self::enter)
+    /// is called. Sources are applied in the order they were added; at most
+    /// [`MAX_WAKE_SOURCES`] can be attached to a single builder. In
+    /// Sleep/Stop modes its EXTI line is recorded in
+    /// [`wake_triggers`](Self::wake_triggers) immediately (in Standby no
+    /// line is armed or recorded), so [`clear_pending_events`](Self::clear_pending_events)
+    /// can be called before `enter` as documented, not just after it.
+    pub fn add_wake_source(mut self, source: &'a dyn WakeSource) -> Self {
+        if self.wake_source_count < MAX_WAKE_SOURCES {
+            self.wake_sources[self.wake_source_count] = Some(source);
+            self.wake_source_count += 1;
+            if self.is_sleep_or_stop_mode() {
+                self.wake_triggers.record(source.line());
+            }
         }
         self
     }
 
-    /// Wake up the MCU using the WKUP pin (PA0)
-    pub fn enable_wakeup_pin(mut self, nvic: &mut NVIC, exti: &mut EXTI, apb1: &mut APB1) -> Self {
-        // self.enable_wakeup_pin = true;
+    /// Wake up the MCU using the RTC alarm, `secs` seconds after
+    /// [`enter`](Self::enter) is called: `enter` clears the alarm flag and
+    /// calls `rtc.set_alarm()` itself. This is a convenience wrapper around
+    /// [`add_wake_source`](Self::add_wake_source) with a [`RtcAlarmWakeup`].
+    pub fn enable_wakeup_alarm(mut self, secs: u32, rtc: &'a mut Rtc) -> Self {
+        self.wakeup_alarm = Some(secs);
+        self.rtc = Some(rtc);
+        self.add_wake_source(&RtcAlarmWakeup)
+    }
 
-        // Enable power interface clock in RCC_APB1ENR register
+    /// Wake up the MCU using the WKUP pin (PA0). This is a convenience
+    /// wrapper around [`add_wake_source`](Self::add_wake_source) with a
+    /// [`WakeupPinWakeup`].
+    ///
+    /// Takes `apb1` to enable the power interface clock (RCC_APB1ENR PWREN)
+    /// and sets `PWR_CSR.EWUP` here, unconditionally, rather than in
+    /// [`WakeupPinWakeup::apply`](WakeSource::apply): EWUP is what actually
+    /// enables WKUP-pin wakeup from `SleepMode::Standby`, which `apply` never
+    /// runs for (it only runs for `Sleep`/`Stop`, to arm the separate
+    /// EXTI0/NVIC path those modes need).
+    pub fn enable_wakeup_pin(mut self, apb1: &mut APB1) -> Self {
         apb1.set_pwren();
-
-        // Enable WKUP pin (PA0)
         self.pwr.csr.modify(|_, w| w.ewup().set_bit());
+        self.add_wake_source(&WakeupPinWakeup)
+    }
 
+    /// Wake up the MCU on `edge` of `pin`'s EXTI line, e.g. a falling edge
+    /// on PB3 rather than being limited to a rising edge on the dedicated
+    /// WKUP pin. `pin` is taken by value so the type system guarantees it
+    /// has already been configured as an input; it is only used to read off
+    /// its EXTI line/port and is dropped once armed. `afio` is needed to
+    /// point the EXTI line's AFIO_EXTICRx mux at `pin`'s port -- without it,
+    /// the line would keep watching whichever port last happened to be
+    /// routed onto it, not necessarily `pin`'s.
+    pub fn enable_wakeup_gpio<P: WakeupPin>(mut self, pin: P, edge: WakeupEdge, afio: &mut afio::Parts, exti: &mut EXTI, nvic: &mut NVIC) -> Self {
         if self.is_sleep_or_stop_mode() {
-            // 0. Enable EXTI0
-            nvic.enable(EXTI0);
-            NVIC::unpend(EXTI0);
-
-            // 1. Enable line 0 (PA0) in the IMR or EMR
-            match self.sleep_mode_entry {
-                SleepModeEntry::WFI =>
-                    // Interrupt Mask Register
-                    exti.imr.modify(|_, w| w.mr0().set_bit()),
-                SleepModeEntry::WFE =>
-                    // Event Mask Register
-                    exti.emr.modify(|_, w| w.mr0().set_bit()),
-            }
-
-            // 2. Enable rising edge trigger on line 0
-            exti.rtsr.modify(|_, w| w.tr0().set_bit());
-
-            // 3. Clear pending bit for line 0
-            exti.pr.modify(|_, w| w.pr0().set_bit());
+            let line = pin.exti_line();
+            afio_route_line(afio, line, pin.afio_port());
+            exti_arm_line(exti, nvic, line, self.sleep_mode_entry, edge);
+            self.wake_triggers.record(line);
         }
-
         self
     }
 
@@ -177,25 +602,144 @@ impl<'a> SleepModeBuilder<'a> {
         self
     }
 
-    /// Perform a Wait for interrupt or Wait for event instruction, this
-    /// will immediately put the MCU to sleep. This function is always the
-    /// last method we call on the `SleepModeBuilder` (therefore it consumes
-    /// it).
-    pub fn enter(self) {
+    /// Stash `words` (at most [`MAX_CONTEXT_WORDS`]) into the battery-backed
+    /// BKP data registers before calling [`enter`](Self::enter). Waking up
+    /// from Standby resets the MCU and loses all RAM, so this is the only
+    /// way to carry state (e.g. a phase counter) across that reset; read it
+    /// back at startup with [`restore_context`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `words.len() > MAX_CONTEXT_WORDS`.
+    pub fn save_context(&mut self, bkp: &mut BackupDomain, words: &[u16]) {
+        assert!(words.len() <= MAX_CONTEXT_WORDS);
+
+        // BKP data registers are numbered DR1..DR42, there is no DR0.
+        bkp.write_data_register(1, CONTEXT_MAGIC);
+        for (i, word) in words.iter().enumerate() {
+            bkp.write_data_register(2 + i as u8, *word);
+        }
+    }
+
+    /// Switch SYSCLK to HSI at a prescaler that brings HCLK down to at most
+    /// `target_hz`, lowering flash wait states to match, before entering
+    /// Sleep/Stop. This cuts current draw in the modes where the regulator
+    /// and some clocks stay live. Returns a guard: call
+    /// [`ScaledClocksGuard::restore`] right after waking up (for Sleep/Stop,
+    /// which continue in place) to reprogram the PLL/flash latency back to
+    /// exactly the pre-sleep state.
+    pub fn with_scaled_clocks(&mut self, _rcc: &mut Rcc, target_hz: Hertz) -> ScaledClocksGuard {
+        // Safety: we only touch the clock-source/prescaler bits in RCC_CFGR
+        // and the wait-state bits in FLASH_ACR, and `ScaledClocksGuard`
+        // restores them before anything else relies on the clock tree again.
+        let rcc = unsafe { &*RCC::ptr() };
+        let flash = unsafe { &*FLASH::ptr() };
+
+        let guard = ScaledClocksGuard {
+            cfgr: rcc.cfgr.read().bits(),
+            flash_latency: flash.acr.read().latency().bits(),
+        };
+
+        // Switch to HSI before reprogramming the prescalers so we're never
+        // running off a PLL that's about to change multiplier/source. The
+        // flash wait states set up for the faster pre-sleep clock tree are
+        // still in effect here, so this is safe at the higher SYSCLK speed.
+        rcc.cfgr.modify(|_, w| unsafe { w.sw().bits(0b00) });
+        while rcc.cfgr.read().sws().bits() != 0b00 {}
+
+        rcc.cfgr.modify(|_, w| unsafe {
+            w.hpre().bits(hpre_bits_for(target_hz))
+             .ppre1().bits(0b100) // divide by 2, safely under the 36 MHz APB1 limit
+             .ppre2().bits(0b000) // no division needed, APB2 has no upper limit below 72 MHz
+        });
+
+        // Only now, with HCLK actually running at the slower rate, is it
+        // safe to drop the wait states; widen them back out in
+        // `ScaledClocksGuard::restore` before speeding the clock back up.
+        flash.acr.modify(|_, w| unsafe { w.latency().bits(0) });
+
+        guard
+    }
+
+    /// Arm every attached [`WakeSource`], then perform a Wait for Interrupt
+    /// or Wait for Event instruction; this immediately puts the MCU to
+    /// sleep. This function is always the last method we call on the
+    /// `SleepModeBuilder` (therefore it consumes it).
+    ///
+    /// Returns the number of seconds actually spent asleep, as measured by
+    /// the RTC counter passed to [`enable_wakeup_alarm`](Self::enable_wakeup_alarm)
+    /// -- or `0` if no RTC was attached. This return value is only
+    /// meaningful for [`SleepMode::Sleep`]/[`SleepMode::StopRegulatorOn`]/
+    /// [`SleepMode::StopRegulatorLP`], which resume execution here; Standby
+    /// resets the MCU instead, so `enter` never returns in that case. To
+    /// measure residency across a Standby sleep, compare the RTC counter
+    /// read at startup to the alarm value you set before sleeping.
+    pub fn enter(mut self, exti: &mut EXTI, nvic: &mut NVIC) -> u32 {
+        let mut rtc = self.rtc.take();
+
         if let Some(secs) = self.wakeup_alarm {
-            if let Some(rtc) = self.rtc {
+            if let Some(rtc) = rtc.as_deref_mut() {
                 let now = rtc.seconds();
                 rtc.clear_alarm_flag();
                 rtc.set_alarm(now + secs);
             }
         }
 
+        if self.is_sleep_or_stop_mode() {
+            for source in self.wake_sources[..self.wake_source_count].iter().flatten() {
+                source.apply(self.pwr, exti, nvic, self.sleep_mode_entry);
+            }
+        }
+
+        // Sample the RTC counter right before sleeping, so the elapsed time
+        // computed below reflects time actually spent asleep rather than
+        // time spent arming wake sources.
+        let asleep_since = rtc.as_deref().map(|rtc| rtc.seconds());
+
         match self.sleep_mode_entry {
             SleepModeEntry::WFI => asm::wfi(),
-            SleepModeEntry::WFE => asm::wfe(),
+            SleepModeEntry::WFE => {
+                // A bare `wfe()` can return immediately on a stale event
+                // already latched in the Cortex-M event register. The first
+                // WFE below consumes any such stale event, `sev()` sets a
+                // fresh local event and the second WFE immediately clears
+                // it again -- leaving the event register clear, so the
+                // third, actually-blocking WFE only returns on a genuine
+                // new event.
+                asm::wfe();
+                asm::sev();
+                asm::wfe();
+                asm::wfe();
+            }
+        }
+
+        match (asleep_since, rtc) {
+            (Some(since), Some(rtc)) => rtc.seconds().wrapping_sub(since),
+            _ => 0,
+        }
+    }
+
+    /// Clear the EXTI pending bits for every line armed by
+    /// [`add_wake_source`](Self::add_wake_source)/`enable_wakeup_*`. Useful
+    /// to call right before [`enter`](Self::enter) if a wake source's line
+    /// may have latched a pending event earlier in the run (e.g. while
+    /// polling the same pin) that would otherwise cause an immediate,
+    /// spurious wakeup.
+    pub fn clear_pending_events(&self, exti: &mut EXTI) {
+        for line in self.wake_triggers.lines().iter().flatten() {
+            exti_clear_pending(exti, *line);
         }
     }
 
+    /// The EXTI lines armed so far by
+    /// [`add_wake_source`](Self::add_wake_source)/`enable_wakeup_*`.
+    /// Populated as each source is added, so this (and
+    /// [`clear_pending_events`](Self::clear_pending_events)) reflects every
+    /// source attached so far even before [`enter`](Self::enter) has run.
+    pub fn wake_triggers(&self) -> &WakeTriggers {
+        &self.wake_triggers
+    }
+
     fn is_sleep_or_stop_mode(&self) -> bool {
         match self.sleep_mode {
             SleepMode::Sleep => true,
@@ -205,3 +749,22 @@ impl<'a> SleepModeBuilder<'a> {
         }
     }
 }
+
+/// Read back the context saved by [`SleepModeBuilder::save_context`].
+///
+/// Returns `None` on a cold boot (no context was ever saved, so the magic
+/// value is absent) — `buf` is left untouched in that case. Returns
+/// `Some(n)` on a wakeup-from-Standby boot, with the first `n` elements of
+/// `buf` filled in (`n` is `buf.len()` clamped to [`MAX_CONTEXT_WORDS`]).
+pub fn restore_context(bkp: &BackupDomain, buf: &mut [u16]) -> Option<usize> {
+    // BKP data registers are numbered DR1..DR42, there is no DR0.
+    if bkp.read_data_register(1) != CONTEXT_MAGIC {
+        return None;
+    }
+
+    let n = buf.len().min(MAX_CONTEXT_WORDS);
+    for (i, slot) in buf.iter_mut().take(n).enumerate() {
+        *slot = bkp.read_data_register(2 + i as u8);
+    }
+    Some(n)
+}